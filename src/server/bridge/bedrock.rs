@@ -0,0 +1,379 @@
+//! Bedrock Edition proxying.
+//!
+//! Bedrock speaks RakNet over UDP rather than the Java TCP handshake, so it
+//! can't reuse the `TcpStream`-based [`super::ConnectionPrimer`] machinery:
+//! there is no single "connection" to prime, only a stream of datagrams tied
+//! together by a per-client session. This module runs as a parallel
+//! subsystem that owns its own socket and session table.
+//!
+//! RakNet doesn't carry the client's address inside its packets the way the
+//! Java handshake does, so there's no field to rewrite - the only way for
+//! the backend to see the real player IP is for the session's backend-facing
+//! socket to *source its datagrams from that IP*. When `spoof_client_ip` is
+//! enabled we do that with `IP_TRANSPARENT`, binding each session's backend
+//! socket to the client's own address; this requires `CAP_NET_ADMIN` and a
+//! TPROXY routing rule directing `backend_addr` traffic back through hopper.
+//! That's an unusual deployment, so it's opt-in and falls back to a regular
+//! backend-sourced socket (the backend then just sees hopper's address,
+//! same as an unconfigured RealIP/BungeeCord setup) whenever it's disabled
+//! or unavailable.
+//!
+//! A single client's traffic is never allowed to take the whole subsystem
+//! down: every datagram is handled independently and a failure handling one
+//! is logged and skipped rather than propagated out of the accept loop.
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::HopperError;
+
+// https://wiki.vg/Raknet_Protocol#Data_packets. everything other than an
+// unconnected ping (open-connection handshake 0x05/0x07, and connected
+// datagrams 0x80-0x8f) is just relayed, so only these two ids matter here
+const UNCONNECTED_PING: u8 = 0x01;
+const UNCONNECTED_PONG: u8 = 0x1c;
+
+// fixed 16-byte magic every unconnected RakNet packet starts with
+const RAKNET_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the MOTD fields advertised in the unconnected pong, i.e. the Bedrock
+/// equivalent of the Java status response
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BedrockMotd {
+    pub motd: String,
+    pub protocol_version: i32,
+    pub version_name: String,
+    pub player_count: i32,
+    pub max_players: i32,
+    pub server_guid: u64,
+}
+
+impl BedrockMotd {
+    fn to_pong_payload(&self) -> String {
+        format!(
+            "MCPE;{};{};{};{};{};{};hopper;Survival;1;{};{}",
+            self.motd,
+            self.protocol_version,
+            self.version_name,
+            self.player_count,
+            self.max_players,
+            self.server_guid,
+            19132,
+            19133,
+        )
+    }
+}
+
+/// one RakNet client<->backend pairing, keyed by the client's address
+struct BedrockSession {
+    backend: Arc<UdpSocket>,
+}
+
+type SessionMap = Arc<Mutex<HashMap<SocketAddr, BedrockSession>>>;
+
+/// a standalone Bedrock/RakNet listener, run alongside the Java TCP listener
+pub struct BedrockServer {
+    socket: Arc<UdpSocket>,
+    motd: BedrockMotd,
+    backend_addr: SocketAddr,
+    spoof_client_ip: bool,
+    sessions: SessionMap,
+}
+
+impl BedrockServer {
+    pub async fn bind(
+        bind_addr: SocketAddr,
+        backend_addr: SocketAddr,
+        motd: BedrockMotd,
+        spoof_client_ip: bool,
+    ) -> Result<Self, HopperError> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            motd,
+            backend_addr,
+            spoof_client_ip,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// runs the datagram pump forever, relaying RakNet traffic between
+    /// clients and the configured backend. a single client's datagram
+    /// failing to handle (a bad session, a momentarily-down backend, ...)
+    /// is logged and skipped rather than tearing down every other client
+    pub async fn run(self) -> Result<(), HopperError> {
+        let mut buf = [0u8; 2048];
+
+        loop {
+            let (len, client_addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(pair) => pair,
+                Err(err) => {
+                    tracing::warn!(error = %err, "bedrock: failed to receive datagram");
+                    continue;
+                }
+            };
+            let packet = &buf[..len];
+
+            let Some(&id) = packet.first() else {
+                continue;
+            };
+
+            let result = if id == UNCONNECTED_PING {
+                self.handle_unconnected_ping(packet, client_addr).await
+            } else {
+                // both the open-connection handshake (0x05/0x07) and the
+                // already-connected datagrams (0x80-0x8f) just get relayed
+                self.relay_to_backend(packet, client_addr).await
+            };
+
+            if let Err(err) = result {
+                tracing::warn!(error = %err, %client_addr, "bedrock: failed to handle datagram");
+            }
+        }
+    }
+
+    async fn handle_unconnected_ping(
+        &self,
+        packet: &[u8],
+        client_addr: SocketAddr,
+    ) -> Result<(), HopperError> {
+        if !is_valid_unconnected_ping(packet) {
+            return Ok(());
+        }
+
+        let payload = self.motd.to_pong_payload();
+        let pong = build_unconnected_pong(&packet[1..9], self.motd.server_guid, &payload);
+
+        self.socket.send_to(&pong, client_addr).await?;
+        Ok(())
+    }
+
+    /// relays a datagram to the backend, opening a new per-client session
+    /// (and its own backend socket, sourced from the client's own address
+    /// when spoofing is enabled and available) if one doesn't exist yet. the
+    /// session map is only held long
+    /// enough to look up or insert the session, never across the send
+    async fn relay_to_backend(
+        &self,
+        packet: &[u8],
+        client_addr: SocketAddr,
+    ) -> Result<(), HopperError> {
+        let backend = {
+            let mut sessions = self.sessions.lock().await;
+
+            match sessions.get(&client_addr) {
+                Some(session) => session.backend(),
+                None => {
+                    let backend = Arc::new(open_backend_socket(
+                        client_addr,
+                        self.backend_addr,
+                        self.spoof_client_ip,
+                    )?);
+                    sessions.insert(
+                        client_addr,
+                        BedrockSession {
+                            backend: backend.clone(),
+                        },
+                    );
+                    self.spawn_backend_pump(client_addr, backend.clone());
+                    backend
+                }
+            }
+        };
+
+        backend.send(packet).await?;
+        Ok(())
+    }
+
+    /// pumps datagrams coming back from the backend for a given session
+    /// back out to the client it belongs to
+    fn spawn_backend_pump(&self, client_addr: SocketAddr, backend: Arc<UdpSocket>) {
+        let client_socket = self.socket.clone();
+        let sessions = self.sessions.clone();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let recv = tokio::time::timeout(SESSION_IDLE_TIMEOUT, backend.recv(&mut buf)).await;
+                let len = match recv {
+                    Ok(Ok(len)) => len,
+                    _ => break,
+                };
+
+                if client_socket.send_to(&buf[..len], client_addr).await.is_err() {
+                    break;
+                }
+            }
+
+            sessions.lock().await.remove(&client_addr);
+        });
+    }
+}
+
+/// an unconnected ping is `id(1) + time(8) + magic(16) + client guid(8)`
+fn is_valid_unconnected_ping(packet: &[u8]) -> bool {
+    packet.len() >= 25 && packet[9..25] == RAKNET_MAGIC
+}
+
+/// builds an unconnected pong: `id(1) + echoed time(8) + server guid(8) +
+/// magic(16) + length-prefixed MOTD string`
+fn build_unconnected_pong(ping_time: &[u8], server_guid: u64, payload: &str) -> Vec<u8> {
+    let mut pong = Vec::with_capacity(35 + payload.len());
+    pong.push(UNCONNECTED_PONG);
+    pong.extend_from_slice(ping_time);
+    pong.extend_from_slice(&server_guid.to_be_bytes());
+    pong.extend_from_slice(&RAKNET_MAGIC);
+    pong.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    pong.extend_from_slice(payload.as_bytes());
+    pong
+}
+
+impl BedrockSession {
+    fn backend(&self) -> Arc<UdpSocket> {
+        self.backend.clone()
+    }
+}
+
+/// opens the backend-facing socket for a new session. if spoofing is
+/// enabled, tries to bind it to the client's own address via
+/// `IP_TRANSPARENT` first; falls back to an ordinary backend-sourced socket
+/// if that's disabled or unavailable (e.g. missing `CAP_NET_ADMIN`)
+fn open_backend_socket(
+    client_addr: SocketAddr,
+    backend_addr: SocketAddr,
+    spoof_client_ip: bool,
+) -> Result<UdpSocket, HopperError> {
+    if spoof_client_ip {
+        match spoofed_backend_socket(client_addr, backend_addr) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => tracing::warn!(
+                error = %err,
+                %client_addr,
+                "bedrock: IP_TRANSPARENT unavailable, falling back to a regular backend socket"
+            ),
+        }
+    }
+
+    plain_backend_socket(backend_addr)
+}
+
+/// binds a backend-facing UDP socket whose source address is the client's
+/// own address, via `IP_TRANSPARENT`, so the backend sees the real player IP
+/// instead of the proxy's
+fn spoofed_backend_socket(
+    client_addr: SocketAddr,
+    backend_addr: SocketAddr,
+) -> Result<UdpSocket, HopperError> {
+    let domain = if client_addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&client_addr.into())?;
+    socket.connect(&backend_addr.into())?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into()).map_err(Into::into)
+}
+
+/// an ordinary backend socket, sourced from the proxy's own address - used
+/// when client-IP spoofing is disabled or unavailable
+fn plain_backend_socket(backend_addr: SocketAddr) -> Result<UdpSocket, HopperError> {
+    let bind_addr: SocketAddr = if backend_addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    let socket = Socket::new(
+        if backend_addr.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        },
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&bind_addr.into())?;
+    socket.connect(&backend_addr.into())?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into()).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_motd() -> BedrockMotd {
+        BedrockMotd {
+            motd: "A Hopper Server".to_string(),
+            protocol_version: 671,
+            version_name: "1.21.0".to_string(),
+            player_count: 3,
+            max_players: 20,
+            server_guid: 0x1122334455667788,
+        }
+    }
+
+    #[test]
+    fn pong_payload_matches_bedrock_motd_format() {
+        let payload = sample_motd().to_pong_payload();
+        let fields: Vec<&str> = payload.split(';').collect();
+
+        assert_eq!(fields[0], "MCPE");
+        assert_eq!(fields[1], "A Hopper Server");
+        assert_eq!(fields[2], "671");
+        assert_eq!(fields[3], "1.21.0");
+        assert_eq!(fields[4], "3");
+        assert_eq!(fields[5], "20");
+        assert_eq!(fields[6], "1234605616436508552");
+    }
+
+    #[test]
+    fn unconnected_ping_requires_full_magic() {
+        let mut packet = vec![0u8; 25];
+        packet[0] = UNCONNECTED_PING;
+        packet[9..25].copy_from_slice(&RAKNET_MAGIC);
+        assert!(is_valid_unconnected_ping(&packet));
+
+        // too short to contain the magic at all
+        assert!(!is_valid_unconnected_ping(&packet[..24]));
+
+        // right length, but the bytes at the magic offset don't match
+        let mut corrupted = packet.clone();
+        corrupted[9] ^= 0xff;
+        assert!(!is_valid_unconnected_ping(&corrupted));
+    }
+
+    #[test]
+    fn unconnected_pong_framing() {
+        let ping_time = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let pong = build_unconnected_pong(&ping_time, 42, "MCPE;test;1;2;3;4;5;6");
+
+        assert_eq!(pong[0], UNCONNECTED_PONG);
+        assert_eq!(&pong[1..9], &ping_time);
+        assert_eq!(&pong[9..17], &42u64.to_be_bytes());
+        assert_eq!(&pong[17..33], &RAKNET_MAGIC);
+
+        let payload_len = u16::from_be_bytes([pong[33], pong[34]]) as usize;
+        assert_eq!(payload_len, "MCPE;test;1;2;3;4;5;6".len());
+        assert_eq!(&pong[35..35 + payload_len], b"MCPE;test;1;2;3;4;5;6");
+    }
+}
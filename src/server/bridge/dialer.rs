@@ -0,0 +1,155 @@
+//! Resilient backend dialing.
+//!
+//! A route can resolve to more than one candidate endpoint (a primary plus
+//! fallbacks, and optionally a relay used only if every direct candidate is
+//! unreachable). Modeled on magic-wormhole's transit hints: give the primary
+//! candidate a short head start, then race the rest, take the first
+//! established TCP connection and drop the losers. The result is handed to
+//! [`super::ConnectionPrimer`] exactly like a single-address connect would
+//! have been.
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::Deserialize;
+use tokio::{net::TcpStream, task::JoinSet};
+
+use crate::HopperError;
+
+const PRIMARY_HEAD_START: Duration = Duration::from_millis(250);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a backend route that may resolve to several dialable endpoints
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendRoute {
+    pub primary: SocketAddr,
+    #[serde(default)]
+    pub fallbacks: Vec<SocketAddr>,
+    #[serde(default)]
+    pub relay: Option<SocketAddr>,
+}
+
+/// dials a [`BackendRoute`] with a happy-eyeballs strategy: the primary
+/// candidate gets a head start, then every fallback is raced concurrently,
+/// and the first successful TCP connect wins while the rest are aborted.
+/// if every direct candidate fails, falls back to the configured relay
+pub async fn dial(route: &BackendRoute) -> Result<TcpStream, HopperError> {
+    let mut attempts = JoinSet::new();
+    attempts.spawn(connect_candidate(route.primary));
+
+    let mut last_err = None;
+
+    tokio::select! {
+        _ = tokio::time::sleep(PRIMARY_HEAD_START) => {}
+        Some(result) = attempts.join_next() => {
+            match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some(err),
+                Err(join_err) => last_err = Some(join_error_to_hopper(join_err)),
+            }
+        }
+    }
+
+    for &addr in &route.fallbacks {
+        attempts.spawn(connect_candidate(addr));
+    }
+
+    while let Some(result) = attempts.join_next().await {
+        match result {
+            Ok(Ok(stream)) => {
+                attempts.abort_all();
+                return Ok(stream);
+            }
+            Ok(Err(err)) => last_err = Some(err),
+            Err(join_err) => last_err = Some(join_error_to_hopper(join_err)),
+        }
+    }
+
+    match route.relay {
+        Some(relay) => connect_candidate(relay).await,
+        None => Err(last_err.unwrap_or(HopperError::Invalid)),
+    }
+}
+
+async fn connect_candidate(addr: SocketAddr) -> Result<TcpStream, HopperError> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out").into())
+        }
+    }
+}
+
+/// a connect attempt can fail either because the connect itself errored, or
+/// because the task running it panicked/was cancelled; surface the latter
+/// too instead of silently falling back to a generic error
+fn join_error_to_hopper(err: tokio::task::JoinError) -> HopperError {
+    std::io::Error::other(err.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn fallbacks_and_relay_default_to_empty_when_omitted() {
+        let route: BackendRoute = serde_json::from_str(r#"{"primary": "127.0.0.1:25565"}"#)
+            .expect("a bare primary should be enough to deserialize a route");
+
+        assert!(route.fallbacks.is_empty());
+        assert!(route.relay.is_none());
+    }
+
+    #[tokio::test]
+    async fn dial_falls_back_to_a_working_candidate() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback_addr = fallback.local_addr().unwrap();
+
+        // nothing is listening on this port, so the primary connect fails
+        let dead_primary: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let route = BackendRoute {
+            primary: dead_primary,
+            fallbacks: vec![fallback_addr],
+            relay: None,
+        };
+
+        let stream = dial(&route).await.expect("fallback should have connected");
+        assert_eq!(stream.peer_addr().unwrap(), fallback_addr);
+    }
+
+    #[tokio::test]
+    async fn dial_falls_back_to_relay_when_every_direct_candidate_fails() {
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+
+        let dead: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let route = BackendRoute {
+            primary: dead,
+            fallbacks: vec![dead],
+            relay: Some(relay_addr),
+        };
+
+        let stream = dial(&route).await.expect("relay should have connected");
+        assert_eq!(stream.peer_addr().unwrap(), relay_addr);
+    }
+
+    #[tokio::test]
+    async fn dial_prefers_the_primary_when_it_answers_within_the_head_start() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_addr = primary.local_addr().unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback_addr = fallback.local_addr().unwrap();
+
+        let route = BackendRoute {
+            primary: primary_addr,
+            fallbacks: vec![fallback_addr],
+            relay: None,
+        };
+
+        let stream = dial(&route).await.expect("primary should have connected");
+        assert_eq!(stream.peer_addr().unwrap(), primary_addr);
+    }
+}
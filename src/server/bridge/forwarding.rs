@@ -1,10 +1,17 @@
 use std::{fmt::Write, net::SocketAddr};
 
+use hmac::{Hmac, Mac};
 use serde::Deserialize;
+use sha2::Sha256;
 use tokio::net::TcpStream;
 
 use crate::{
-    protocol::{lazy::DecodedPacket, packet, packets::Handshake, uuid::PlayerUuid},
+    protocol::{
+        lazy::DecodedPacket,
+        packet,
+        packets::{Handshake, LoginPluginRequest, LoginPluginResponse, LoginStart},
+        uuid::PlayerUuid,
+    },
     HopperError,
 };
 
@@ -20,6 +27,22 @@ pub enum ForwardStrategy {
     // RealIP <=2.4 support
     #[serde(rename = "realip")]
     RealIP,
+
+    // Velocity modern forwarding
+    #[serde(rename = "velocity")]
+    Velocity,
+}
+
+/// a single entry of a player's game profile, as returned by
+/// Mojang session authentication. Shared by the primers that need
+/// to hand the backend the player's skin/textures rather than just
+/// their identity
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct GameProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[async_trait::async_trait]
@@ -28,10 +51,15 @@ pub trait ConnectionPrimer {
     /// which may be with address forwarding informations
     /// or not, up to the implementer
     ///
+    /// `client_stream` is the client-facing side of the connection, needed
+    /// by primers that have to pump further login-phase packets (e.g. Login
+    /// Start) across before the backend will proceed
+    ///
     /// `og_handshake` is the original handshake that was sent to hoppper
     /// by the client
     async fn prime_connection(
         self,
+        client_stream: &mut TcpStream,
         stream: &mut TcpStream,
         og_handshake: DecodedPacket<Handshake>,
     ) -> Result<(), HopperError>;
@@ -40,6 +68,7 @@ pub trait ConnectionPrimer {
 pub(super) struct BungeeCord {
     player_addr: SocketAddr,
     player_uuid: PlayerUuid,
+    properties: Option<Vec<GameProfileProperty>>,
 }
 
 impl BungeeCord {
@@ -50,6 +79,40 @@ impl BungeeCord {
             // ignored by online-mode servers so we can always send
             // it even when the server is premium-only
             player_uuid: PlayerUuid::offline_player(player_name),
+            properties: None,
+        }
+    }
+
+    /// same as [`BungeeCord::from_username`], but for an authenticated,
+    /// online-mode player: carries the real UUID and game profile
+    /// properties (skin/textures) through to the backend
+    pub fn from_profile(
+        player_addr: SocketAddr,
+        player_uuid: PlayerUuid,
+        properties: Vec<GameProfileProperty>,
+    ) -> Self {
+        Self {
+            player_addr,
+            player_uuid,
+            properties: Some(properties),
+        }
+    }
+
+    /// appends the `\x00ip\x00uuid[\x00properties_json]` fields BungeeCord
+    /// expects onto `server_address`, split out so the byte layout can be
+    /// unit-tested without a real connection
+    fn append_forwarding_fields(&self, server_address: &mut String) {
+        write!(
+            server_address,
+            "\x00{}\x00{}",
+            self.player_addr.ip(),
+            self.player_uuid
+        )
+        .unwrap();
+
+        if let Some(properties) = &self.properties {
+            let properties_json = serde_json::to_string(properties).unwrap();
+            write!(server_address, "\x00{properties_json}").unwrap();
         }
     }
 }
@@ -58,6 +121,7 @@ impl BungeeCord {
 impl ConnectionPrimer for BungeeCord {
     async fn prime_connection(
         self,
+        _client_stream: &mut TcpStream,
         stream: &mut TcpStream,
         og_handshake: DecodedPacket<Handshake>,
     ) -> Result<(), HopperError> {
@@ -71,13 +135,9 @@ impl ConnectionPrimer for BungeeCord {
         }
 
         // https://github.com/SpigotMC/BungeeCord/blob/8d494242265790df1dc6d92121d1a37b726ac405/proxy/src/main/java/net/md_5/bungee/ServerConnector.java#L91-L106
-        write!(
-            handshake.server_address,
-            "\x00{}\x00{}",
-            self.player_addr.ip(),
-            self.player_uuid
-        )
-        .unwrap();
+        // second field is the ip, third the uuid, fourth (optional) the game
+        // profile properties
+        self.append_forwarding_fields(&mut handshake.server_address);
 
         // send the modified handshake
         packet::write_serialize(handshake, stream).await?;
@@ -100,6 +160,7 @@ impl RealIP {
 impl ConnectionPrimer for RealIP {
     async fn prime_connection(
         self,
+        _client_stream: &mut TcpStream,
         stream: &mut TcpStream,
         og_handshake: DecodedPacket<Handshake>,
     ) -> Result<(), HopperError> {
@@ -132,6 +193,168 @@ impl ConnectionPrimer for RealIP {
     }
 }
 
+type HmacSha256 = Hmac<Sha256>;
+
+// https://github.com/PaperMC/Velocity/blob/dev/3.0.0/proxy/src/main/java/com/velocitypowered/proxy/connection/backend/LoginSessionHandler.java
+const VELOCITY_CHANNEL: &str = "velocity:player_info";
+// highest forwarding version we know how to produce; the backend tells us
+// the highest it understands and we have to meet it at whichever is lower
+const VELOCITY_MAX_FORWARDING_VERSION: i32 = 1;
+
+/// Velocity modern forwarding. Unlike `BungeeCord`/`RealIP` this can't be
+/// primed by rewriting the handshake alone: the backend asks for the
+/// player's info during the login phase via a login plugin message on
+/// `velocity:player_info`, which we have to answer with an HMAC-signed
+/// payload describing the player. Unlike `BungeeCord::from_username`, the
+/// username isn't known up front: it comes off the client's Login Start,
+/// which this primer has to intercept and pump across itself
+pub(super) struct Velocity {
+    player_addr: SocketAddr,
+    player_uuid: PlayerUuid,
+    properties: Vec<GameProfileProperty>,
+    secret: Vec<u8>,
+}
+
+impl Velocity {
+    pub fn new(
+        player_addr: SocketAddr,
+        player_uuid: PlayerUuid,
+        properties: Vec<GameProfileProperty>,
+        secret: Vec<u8>,
+    ) -> Self {
+        Self {
+            player_addr,
+            player_uuid,
+            properties,
+            secret,
+        }
+    }
+
+    /// builds the `data` part of the login plugin response, as described
+    /// in Velocity's modern forwarding spec
+    fn forwarding_data(&self, username: &str, forwarding_version: i32) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        write_varint(&mut data, forwarding_version);
+        write_string(&mut data, &self.player_addr.ip().to_string());
+        data.extend_from_slice(self.player_uuid.as_bytes());
+        write_string(&mut data, username);
+
+        write_varint(&mut data, self.properties.len() as i32);
+        for property in &self.properties {
+            write_string(&mut data, &property.name);
+            write_string(&mut data, &property.value);
+            match &property.signature {
+                Some(signature) => {
+                    data.push(1);
+                    write_string(&mut data, signature);
+                }
+                None => data.push(0),
+            }
+        }
+
+        data
+    }
+}
+
+#[async_trait::async_trait]
+impl ConnectionPrimer for Velocity {
+    async fn prime_connection(
+        self,
+        client_stream: &mut TcpStream,
+        stream: &mut TcpStream,
+        og_handshake: DecodedPacket<Handshake>,
+    ) -> Result<(), HopperError> {
+        // the handshake itself isn't touched, forwarding happens entirely
+        // over a login plugin message the backend sends once login starts
+        og_handshake.as_ref().write_into(stream).await?;
+
+        // the backend only sends the velocity:player_info request once it
+        // has seen Login Start, so we have to pump that across ourselves -
+        // and it's also our only source for the player's username
+        let login_start = packet::read_deserialize::<LoginStart>(client_stream)
+            .await?
+            .into_data();
+        let username = login_start.name.clone();
+        packet::write_serialize(login_start, stream).await?;
+
+        let request: DecodedPacket<LoginPluginRequest> = packet::read_deserialize(stream).await?;
+        let request = request.into_data();
+
+        if request.channel != VELOCITY_CHANNEL {
+            return Err(HopperError::Invalid);
+        }
+
+        let requested_version = read_varint(&request.data)?;
+        if requested_version < 1 {
+            return Err(HopperError::Invalid);
+        }
+        let forwarding_version = requested_version.min(VELOCITY_MAX_FORWARDING_VERSION);
+
+        let data = self.forwarding_data(&username, forwarding_version);
+
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).map_err(|_| HopperError::Invalid)?;
+        mac.update(&data);
+        let signature = mac.finalize().into_bytes();
+
+        let mut payload = Vec::with_capacity(signature.len() + data.len());
+        payload.extend_from_slice(&signature);
+        payload.extend_from_slice(&data);
+
+        let response = LoginPluginResponse {
+            message_id: request.message_id,
+            successful: true,
+            data: Some(payload),
+        };
+        packet::write_serialize(response, stream).await?;
+
+        Ok(())
+    }
+}
+
+/// minimal VarInt/String encoders for the velocity forwarding payload,
+/// which is a raw byte blob rather than a typed protocol packet
+fn write_varint(buf: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value = ((value as u32) >> 7) as i32;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_varint(buf, value.len() as i32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// reads the requested-forwarding-version VarInt out of the backend's
+/// `velocity:player_info` request
+fn read_varint(buf: &[u8]) -> Result<i32, HopperError> {
+    let mut value = 0i32;
+    let mut shift = 0u32;
+
+    for &byte in buf {
+        value |= ((byte & 0x7f) as i32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+        if shift >= 32 {
+            break;
+        }
+    }
+
+    Err(HopperError::Invalid)
+}
+
 /// Passthrough primer, does not modify the original
 /// handshake and just sends along bytes as-is
 pub(super) struct Passthrough;
@@ -140,6 +363,7 @@ pub(super) struct Passthrough;
 impl ConnectionPrimer for Passthrough {
     async fn prime_connection(
         self,
+        _client_stream: &mut TcpStream,
         stream: &mut TcpStream,
         og_handshake: DecodedPacket<Handshake>,
     ) -> Result<(), HopperError> {
@@ -148,3 +372,118 @@ impl ConnectionPrimer for Passthrough {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trips() {
+        for value in [0, 1, 2, 127, 128, 255, 300, 2_097_151, i32::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(read_varint(&buf).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn varint_uses_minimal_known_encodings() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1);
+        assert_eq!(buf, vec![0x01]);
+
+        buf.clear();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+    }
+
+    #[test]
+    fn string_is_length_prefixed() {
+        let mut buf = Vec::new();
+        write_string(&mut buf, "hi");
+        assert_eq!(buf, vec![0x02, b'h', b'i']);
+    }
+
+    // RFC 4231 test case 1
+    #[test]
+    fn hmac_sha256_matches_known_vector() {
+        let key = [0x0bu8; 20];
+        let mut mac = HmacSha256::new_from_slice(&key).unwrap();
+        mac.update(b"Hi There");
+        let result = mac.finalize().into_bytes();
+
+        let expected = [
+            0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b,
+            0xf1, 0x2b, 0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c,
+            0x2e, 0x32, 0xcf, 0xf7,
+        ];
+        assert_eq!(&result[..], &expected[..]);
+    }
+
+    #[test]
+    fn velocity_forwarding_data_layout() {
+        let velocity = Velocity::new(
+            "127.0.0.1:25565".parse().unwrap(),
+            PlayerUuid::offline_player("Steve"),
+            vec![GameProfileProperty {
+                name: "textures".to_string(),
+                value: "abc".to_string(),
+                signature: None,
+            }],
+            b"secret".to_vec(),
+        );
+
+        let data = velocity.forwarding_data("Steve", 1);
+
+        let mut expected = Vec::new();
+        write_varint(&mut expected, 1); // forwarding version
+        write_string(&mut expected, "127.0.0.1");
+        expected.extend_from_slice(PlayerUuid::offline_player("Steve").as_bytes());
+        write_string(&mut expected, "Steve");
+        write_varint(&mut expected, 1); // one property
+        write_string(&mut expected, "textures");
+        write_string(&mut expected, "abc");
+        expected.push(0); // no signature
+
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn bungeecord_appends_ip_and_uuid() {
+        let bungee = BungeeCord::from_username("127.0.0.1:25565".parse().unwrap(), "Steve");
+
+        let mut server_address = "play.example.com".to_string();
+        bungee.append_forwarding_fields(&mut server_address);
+
+        assert_eq!(
+            server_address,
+            format!(
+                "play.example.com\x00127.0.0.1\x00{}",
+                PlayerUuid::offline_player("Steve")
+            )
+        );
+    }
+
+    #[test]
+    fn bungeecord_appends_properties_as_fourth_field() {
+        let bungee = BungeeCord::from_profile(
+            "127.0.0.1:25565".parse().unwrap(),
+            PlayerUuid::offline_player("Steve"),
+            vec![GameProfileProperty {
+                name: "textures".to_string(),
+                value: "abc".to_string(),
+                signature: Some("sig".to_string()),
+            }],
+        );
+
+        let mut server_address = "play.example.com".to_string();
+        bungee.append_forwarding_fields(&mut server_address);
+
+        let fields: Vec<&str> = server_address.split('\x00').collect();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(
+            fields[3],
+            r#"[{"name":"textures","value":"abc","signature":"sig"}]"#
+        );
+    }
+}